@@ -9,11 +9,96 @@ use crate::{wasi, wasi32, Error, Result};
 use cpu_time::{ProcessTime, ThreadTime};
 use lazy_static::lazy_static;
 use std::convert::TryInto;
-use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::time::{Duration, Instant};
+use winapi::shared::minwindef::{DWORD, FILETIME};
+use winapi::shared::ntdef::LARGE_INTEGER;
+use winapi::shared::winerror::WAIT_TIMEOUT;
+use winapi::um::consoleapi::{PeekConsoleInputW, ReadConsoleInputW};
+use winapi::um::fileapi::{GetFileType, SetFilePointerEx};
+use winapi::um::libloaderapi::{GetModuleHandleA, GetProcAddress};
+use winapi::um::mmsystem::{TIMECAPS, TIMERR_NOERROR};
+use winapi::um::namedpipeapi::PeekNamedPipe;
+use winapi::um::synchapi::WaitForSingleObject;
+use winapi::um::sysinfoapi::GetSystemTimeAsFileTime;
+use winapi::um::timeapi::timeGetDevCaps;
+use winapi::um::winbase::{FILE_CURRENT, FILE_TYPE_CHAR, FILE_TYPE_PIPE, INFINITE, WAIT_OBJECT_0};
+use winapi::um::wincon::KEY_EVENT;
+use winapi::um::wincontypes::INPUT_RECORD;
+
+/// How long a single iteration of the pipe-polling loop is allowed to sleep for. Short enough
+/// that we notice the deadline passing promptly, long enough that we're not busy-looping.
+const PIPE_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 lazy_static! {
     static ref START_MONOTONIC: Instant = Instant::now();
     static ref PERF_COUNTER_RES: u64 = get_perf_counter_resolution_ns();
+    // `GetSystemTimePreciseAsFileTime` is only available on Windows 8 / Server 2012 and newer, so
+    // we have to resolve it at runtime rather than link against it directly.
+    static ref GET_SYSTEM_TIME_PRECISE_AS_FILE_TIME: Option<GetSystemTimePreciseAsFileTimeFn> =
+        unsafe { load_system_time_precise_as_file_time() };
+    // The actual granularity of the system clock, queried once on first use and then cached. This
+    // is the timer resolution the host had in effect at that point, rather than a pessimistic
+    // worst case.
+    static ref TIMER_RES: u64 = get_timer_resolution_ns();
+}
+
+/// Queries the current system timer resolution, in nanoseconds.
+///
+/// `NtQueryTimerResolution` is undocumented (and so isn't exposed by the `winapi` crate), but is
+/// the only way to get the *current* resolution rather than the min/max bounds `timeGetDevCaps`
+/// reports; we fall back to the latter's minimum period on systems/configurations where resolving
+/// it fails.
+fn get_timer_resolution_ns() -> u64 {
+    if let Some(resolution) = unsafe { nt_query_timer_resolution() } {
+        return resolution;
+    }
+
+    let mut caps: TIMECAPS = unsafe { std::mem::zeroed() };
+    let rc = unsafe { timeGetDevCaps(&mut caps, std::mem::size_of::<TIMECAPS>() as DWORD) };
+    if rc == TIMERR_NOERROR {
+        u64::from(caps.wPeriodMin) * 1_000_000
+    } else {
+        // Last-ditch fallback matching the constant we used to hardcode unconditionally.
+        55_000_000
+    }
+}
+
+type NtQueryTimerResolutionFn = unsafe extern "system" fn(*mut DWORD, *mut DWORD, *mut DWORD) -> i32;
+
+unsafe fn nt_query_timer_resolution() -> Option<u64> {
+    let module = GetModuleHandleA(b"ntdll.dll\0".as_ptr() as *const i8);
+    if module.is_null() {
+        return None;
+    }
+    let proc = GetProcAddress(module, b"NtQueryTimerResolution\0".as_ptr() as *const i8);
+    if proc.is_null() {
+        return None;
+    }
+    let nt_query_timer_resolution: NtQueryTimerResolutionFn = std::mem::transmute(proc);
+
+    let (mut minimum, mut maximum, mut current) = (0, 0, 0);
+    if nt_query_timer_resolution(&mut minimum, &mut maximum, &mut current) < 0 {
+        return None;
+    }
+    // The resolution is reported in 100ns intervals.
+    Some(u64::from(current) * 100)
+}
+
+type GetSystemTimePreciseAsFileTimeFn = unsafe extern "system" fn(*mut FILETIME);
+
+unsafe fn load_system_time_precise_as_file_time() -> Option<GetSystemTimePreciseAsFileTimeFn> {
+    let module = GetModuleHandleA(b"kernel32.dll\0".as_ptr() as *const i8);
+    if module.is_null() {
+        return None;
+    }
+    let proc = GetProcAddress(module, b"GetSystemTimePreciseAsFileTime\0".as_ptr() as *const i8);
+    if proc.is_null() {
+        None
+    } else {
+        Some(std::mem::transmute(proc))
+    }
 }
 
 // Timer resolution on Windows is really hard. We may consider exposing the resolution of the respective
@@ -27,7 +112,8 @@ pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__
         // for this method. [3]
         //
         // The timer resolution can be queried using one of the functions: [2, 5]
-        // * NtQueryTimerResolution, which is undocumented and thus not exposed by the winapi crate
+        // * NtQueryTimerResolution, which is undocumented and thus not exposed by the winapi crate,
+        //   but gives us the *current* resolution rather than just bounds on it.
         // * timeGetDevCaps, which returns the upper and lower bound for the precision, in ms.
         // While the upper bound seems like something we could use, it's typically too high to be meaningful.
         // For instance, the intervals return by the syscall are:
@@ -37,12 +123,12 @@ pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__
         // It's possible to manually set the timer resolution, but this sounds like something which should
         // only be done temporarily. [5]
         //
-        // Alternatively, we could possibly use GetSystemTimePreciseAsFileTime in clock_time_get, but
-        // this syscall is only available starting from Windows 8.
-        // (we could possibly emulate it on earlier versions of Windows, see [4])
-        // The MSDN are not clear on the resolution of GetSystemTimePreciseAsFileTime either, but a
-        // Microsoft devblog entry [1] suggests that it kind of combines GetSystemTimeAsFileTime with
-        // QueryPeformanceCounter, which probably means that those two should have the same resolution.
+        // `TIMER_RES` (see `get_timer_resolution_ns`) queries `NtQueryTimerResolution`, falling
+        // back to `timeGetDevCaps`'s minimum period, so this reports the resolution the host had in
+        // effect when it was first queried instead of a pessimistic worst case. We also use
+        // GetSystemTimePreciseAsFileTime in get_realtime_time when it's available (Windows 8+),
+        // which the [1] devblog entry suggests combines GetSystemTimeAsFileTime with
+        // QueryPerformanceCounter, which probably means that those two should have the same resolution.
         //
         // See also this discussion about the use of GetSystemTimePreciseAsFileTime in Python stdlib,
         // which in particular contains some resolution benchmarks.
@@ -53,7 +139,7 @@ pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__
         // [4] https://www.codeproject.com/Tips/1011902/High-Resolution-Time-For-Windows
         // [5] https://stackoverflow.com/questions/7685762/windows-7-timing-functions-how-to-use-getsystemtimeadjustment-correctly
         // [6] https://bugs.python.org/issue19007
-        wasi::__WASI_CLOCK_REALTIME => 55_000_000,
+        wasi::__WASI_CLOCK_REALTIME => *TIMER_RES,
         // std::time::Instant uses QueryPerformanceCounter & QueryPerformanceFrequency internally
         wasi::__WASI_CLOCK_MONOTONIC => *PERF_COUNTER_RES,
         // The best we can do is to hardcode the value from the docs.
@@ -68,8 +154,8 @@ pub(crate) fn clock_res_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__
 
 pub(crate) fn clock_time_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::__wasi_timestamp_t> {
     let duration = match clock_id {
-        wasi::__WASI_CLOCK_REALTIME => get_monotonic_time(),
-        wasi::__WASI_CLOCK_MONOTONIC => get_realtime_time()?,
+        wasi::__WASI_CLOCK_REALTIME => get_realtime_time()?,
+        wasi::__WASI_CLOCK_MONOTONIC => get_monotonic_time(),
         wasi::__WASI_CLOCK_PROCESS_CPUTIME_ID => get_proc_cputime()?,
         wasi::__WASI_CLOCK_THREAD_CPUTIME_ID => get_thread_cputime()?,
         _ => return Err(Error::EINVAL),
@@ -77,11 +163,6 @@ pub(crate) fn clock_time_get(clock_id: wasi::__wasi_clockid_t) -> Result<wasi::_
     duration.as_nanos().try_into().map_err(Into::into)
 }
 
-fn stdin_nonempty() -> bool {
-    use std::io::Read;
-    std::io::stdin().bytes().peekable().peek().is_some()
-}
-
 pub(crate) fn poll_oneoff(
     timeout: Option<ClockEventData>,
     fd_events: Vec<FdEventData>,
@@ -112,10 +193,7 @@ pub(crate) fn poll_oneoff(
         for event in immediate_events {
             let size = match event.descriptor {
                 Descriptor::OsFile(os_file) if event.r#type == wasi::__WASI_EVENTTYPE_FD_READ => {
-                    os_file
-                        .metadata()
-                        .expect("FIXME return a proper error")
-                        .len()
+                    remaining_os_file_bytes(&os_file)
                 }
                 Descriptor::Stdin => panic!("Descriptor::Stdin should have been filtered out"),
                 // On Unix, ioctl(FIONREAD) will return 0 for stdout/stderr. Emulate the same behavior on Windows.
@@ -123,49 +201,274 @@ pub(crate) fn poll_oneoff(
                 // Besides, the spec is unclear what nbytes should actually be for __WASI_EVENTTYPE_FD_WRITE and
                 // the implementation on Unix just returns 0 here, so it's probably fine to do the same on Windows for now.
                 // cf. https://github.com/WebAssembly/WASI/issues/148
-                _ => 0,
+                _ => Ok(0),
+            };
+            let (nbytes, error) = match size {
+                Ok(nbytes) => (nbytes, wasi::__WASI_ESUCCESS),
+                Err(e) => (0, e.as_wasi_errno()),
             };
 
             events.push(wasi::__wasi_event_t {
                 userdata: event.userdata,
                 r#type: event.r#type,
-                error: wasi::__WASI_ESUCCESS,
+                error,
                 u: wasi::__wasi_event_u {
-                    fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
-                        nbytes: size,
-                        flags: 0,
-                    },
+                    fd_readwrite: wasi::__wasi_event_fd_readwrite_t { nbytes, flags: 0 },
                 },
             })
         }
+    } else if stdin_events.is_empty() {
+        // No fd subscriptions at all, just a timeout (the guard at the top of this function
+        // already ruled out having neither): sleep it out and report the single clock event once
+        // it elapses.
+        let ms = timeout_to_millis(&timeout);
+        std::thread::sleep(Duration::from_millis(u64::from(ms)));
+        let userdata = timeout
+            .expect("a timeout must be set if there are no fd subscriptions at all")
+            .userdata;
+        events.push(wasi::__wasi_event_t {
+            userdata,
+            r#type: wasi::__WASI_EVENTTYPE_CLOCK,
+            error: wasi::__WASI_ESUCCESS,
+            u: wasi::__wasi_event_u {
+                fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                    nbytes: 0,
+                    flags: 0,
+                },
+            },
+        });
     } else {
-        // We'd like to do the following:
-        // (1) wait in a non-blocking way for data to be available in stdin, with timeout
-        // (2) find out, how many bytes are there available to be read.
-        // Both of these are non-trivial on Windows
-        // TODO describe
-        // 1 -> WaitForSingleObject doesn't work
-        assert_ne!(stdin_events.len(), 0, "stdin_events should not be empty");
-        unimplemented!("polling stdin on Windows not supported yet");
-        // FIXME actually wait for stdin instead of timeouting
-        // for event in stdin_events {
-        //     events.push(wasi::__wasi_event_t {
-        //         userdata: timeout.unwrap().userdata, // FIXME
-        //         r#type: wasi::__WASI_EVENTTYPE_CLOCK,
-        //         error: wasi::__WASI_ESUCCESS,
-        //         u: wasi::__wasi_event_u {
-        //             fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
-        //                 nbytes: 0,
-        //                 flags: 0,
-        //             },
-        //         },
-        //     });
-        // }
+        // All the remaining events are against stdin, so we need to actually wait for data to
+        // become readable on it (or for the timeout to elapse, if one was given). Every
+        // subscription on stdin observes the same outcome, so report it to each of them.
+        match poll_stdin(&timeout)? {
+            Some(nbytes) => {
+                for event in &stdin_events {
+                    events.push(wasi::__wasi_event_t {
+                        userdata: event.userdata,
+                        r#type: wasi::__WASI_EVENTTYPE_FD_READ,
+                        error: wasi::__WASI_ESUCCESS,
+                        u: wasi::__wasi_event_u {
+                            fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                                nbytes,
+                                flags: 0,
+                            },
+                        },
+                    });
+                }
+            }
+            None => {
+                // There is only ever a single clock subscription backing `timeout`, so it fires
+                // exactly once, regardless of how many stdin subscriptions were waiting on it.
+                let userdata = timeout
+                    .expect("timeout must be set if stdin never became readable")
+                    .userdata;
+                events.push(wasi::__wasi_event_t {
+                    userdata,
+                    r#type: wasi::__WASI_EVENTTYPE_CLOCK,
+                    error: wasi::__WASI_ESUCCESS,
+                    u: wasi::__wasi_event_u {
+                        fd_readwrite: wasi::__wasi_event_fd_readwrite_t {
+                            nbytes: 0,
+                            flags: 0,
+                        },
+                    },
+                });
+            }
+        }
     }
 
     Ok(())
 }
 
+/// Converts a `ClockEventData` timeout (given in nanoseconds) to the millisecond resolution
+/// expected by `WaitForSingleObject` & friends, rounding up so that we never wake up before the
+/// requested deadline. `None` means "wait forever".
+fn timeout_to_millis(timeout: &Option<ClockEventData>) -> DWORD {
+    let timeout = match timeout {
+        None => return INFINITE,
+        Some(timeout) => timeout,
+    };
+    let nanos = u64::from(timeout.timeout);
+    let millis = (nanos + 999_999) / 1_000_000;
+    millis.try_into().unwrap_or(DWORD::max_value())
+}
+
+/// Waits for stdin to become readable, or for `timeout` to elapse, whichever comes first.
+/// Returns `Ok(Some(nbytes))` with the number of bytes currently available to read once stdin is
+/// readable, or `Ok(None)` if the timeout elapsed first.
+fn poll_stdin(timeout: &Option<ClockEventData>) -> Result<Option<u64>> {
+    let handle = io::stdin().as_raw_handle();
+    let file_type = unsafe { GetFileType(handle) };
+    match file_type {
+        FILE_TYPE_CHAR => poll_console_stdin(handle, timeout),
+        FILE_TYPE_PIPE => poll_pipe_stdin(handle, timeout),
+        // Regular files (and anything else we don't special-case) are always ready to read
+        // immediately, same as on the Unix side.
+        _ => Ok(Some(readable_os_file_bytes(handle)?)),
+    }
+}
+
+/// Polls a console handle by blocking on it with `WaitForSingleObject` and then, while no
+/// keystroke is pending, draining the queued input records (e.g. key-up, mouse or window-resize
+/// records), which would otherwise keep the handle spuriously signalled.
+fn poll_console_stdin(handle: RawHandle, timeout: &Option<ClockEventData>) -> Result<Option<u64>> {
+    let deadline = Instant::now() + Duration::from_millis(u64::from(timeout_to_millis(timeout)));
+    let has_deadline = timeout.is_some();
+    loop {
+        let ms = if has_deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            remaining.as_millis().try_into().unwrap_or(DWORD::max_value())
+        } else {
+            INFINITE
+        };
+
+        match unsafe { WaitForSingleObject(handle, ms) } {
+            WAIT_OBJECT_0 => {}
+            WAIT_TIMEOUT => return Ok(None),
+            _ => return Err(Error::from(io::Error::last_os_error())),
+        }
+
+        if let Some(nbytes) = drain_non_char_console_events(handle)? {
+            return Ok(Some(nbytes));
+        }
+        // Nothing but uninteresting records were queued up; loop around and wait again for the
+        // remainder of the timeout.
+        if has_deadline && Instant::now() >= deadline {
+            return Ok(None);
+        }
+    }
+}
+
+/// Peeks the console's input buffer (up to 128 records at a time) and returns the number of bytes
+/// that a guest read would currently be able to consume. If none of the peeked records is a
+/// genuine keystroke, they are all consumed (via `ReadConsoleInputW`) and the buffer is peeked
+/// again; `None` is returned once it is empty. Records queued alongside a pending keystroke are
+/// left in the buffer.
+fn drain_non_char_console_events(handle: RawHandle) -> Result<Option<u64>> {
+    loop {
+        let mut buf: [INPUT_RECORD; 128] = unsafe { std::mem::zeroed() };
+        let mut peeked: DWORD = 0;
+        if unsafe {
+            PeekConsoleInputW(handle, buf.as_mut_ptr(), buf.len() as DWORD, &mut peeked)
+        } == 0
+        {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        if peeked == 0 {
+            return Ok(None);
+        }
+
+        let nbytes: u64 = buf[..peeked as usize]
+            .iter()
+            .filter_map(pending_key_event_bytes)
+            .sum();
+        if nbytes > 0 {
+            return Ok(Some(nbytes));
+        }
+
+        // Nothing but stale records (key-up, mouse, focus, window-resize, ...) are queued; drop
+        // them so they stop keeping the handle signalled and check whether more are waiting.
+        let mut read: DWORD = 0;
+        if unsafe {
+            ReadConsoleInputW(handle, buf.as_mut_ptr(), peeked, &mut read)
+        } == 0
+        {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+    }
+}
+
+/// Returns how many bytes a guest read would consume for this record, or `None` if it isn't a
+/// pending keystroke. A held key is reported as a single record with `wRepeatCount` set to the
+/// number of repeats, and the character itself may need more than one UTF-8 byte, so both have to
+/// be accounted for to match what a subsequent read would actually return.
+fn pending_key_event_bytes(record: &INPUT_RECORD) -> Option<u64> {
+    if record.EventType != KEY_EVENT {
+        return None;
+    }
+    let key_event = unsafe { record.Event.KeyEvent() };
+    let code_unit = unsafe { *key_event.uChar.UnicodeChar() };
+    if key_event.bKeyDown == 0 || code_unit == 0 {
+        return None;
+    }
+    // A character outside the BMP arrives as two records, one per surrogate half; each half
+    // accounts for 2 of the character's 4 UTF-8 bytes.
+    let utf8_len = char::from_u32(u32::from(code_unit))
+        .map(char::len_utf8)
+        .unwrap_or(2) as u64;
+    Some(utf8_len * u64::from(key_event.wRepeatCount))
+}
+
+/// Polls a pipe handle by repeatedly peeking it with `PeekNamedPipe` (which doesn't consume any
+/// data) until either bytes show up or the deadline passes.
+fn poll_pipe_stdin(handle: RawHandle, timeout: &Option<ClockEventData>) -> Result<Option<u64>> {
+    let has_deadline = timeout.is_some();
+    let deadline = Instant::now() + Duration::from_millis(u64::from(timeout_to_millis(timeout)));
+    loop {
+        let mut available: DWORD = 0;
+        if unsafe {
+            PeekNamedPipe(
+                handle,
+                std::ptr::null_mut(),
+                0,
+                std::ptr::null_mut(),
+                &mut available,
+                std::ptr::null_mut(),
+            )
+        } == 0
+        {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        if available > 0 {
+            return Ok(Some(u64::from(available)));
+        }
+        if has_deadline && Instant::now() >= deadline {
+            return Ok(None);
+        }
+        std::thread::sleep(PIPE_POLL_INTERVAL);
+    }
+}
+
+/// Returns the number of bytes still unread in `os_file`, i.e. its length minus the current
+/// position of the file cursor, clamped at zero.
+fn remaining_os_file_bytes(os_file: &std::fs::File) -> Result<u64> {
+    let len = os_file.metadata()?.len();
+    let pos = file_cursor_position(os_file)?;
+    Ok(len.saturating_sub(pos))
+}
+
+/// Returns the current position of `file`'s cursor by asking `SetFilePointerEx` to move it by
+/// zero bytes relative to its current position, which is a documented way to query it without
+/// side effects.
+fn file_cursor_position(file: &std::fs::File) -> Result<u64> {
+    let distance: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+    let mut new_position: LARGE_INTEGER = unsafe { std::mem::zeroed() };
+    if unsafe {
+        SetFilePointerEx(
+            file.as_raw_handle(),
+            distance,
+            &mut new_position,
+            FILE_CURRENT,
+        )
+    } == 0
+    {
+        return Err(Error::from(io::Error::last_os_error()));
+    }
+    Ok(unsafe { *new_position.QuadPart() } as u64)
+}
+
+/// Fallback used for stdin handles that are neither a console nor a pipe (e.g. redirected from a
+/// regular file): such handles are always immediately readable, and the bytes left between the
+/// cursor and the end of the file are reported.
+fn readable_os_file_bytes(handle: RawHandle) -> Result<u64> {
+    use std::os::windows::io::FromRawHandle;
+    // We only borrow the handle to read its metadata and cursor; wrap it so we don't close stdin
+    // when the temporary `File` is dropped.
+    let file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_handle(handle) });
+    remaining_os_file_bytes(&file)
+}
+
 fn get_monotonic_time() -> Duration {
     // We're circumventing the fact that we can't get a Duration from an Instant
     // The epoch of __WASI_CLOCK_MONOTONIC is undefined, so we fix a time point once
@@ -176,10 +479,25 @@ fn get_monotonic_time() -> Duration {
     START_MONOTONIC.elapsed()
 }
 
+// The number of 100ns intervals between the Windows FILETIME epoch (1601-01-01) and the Unix
+// epoch (1970-01-01).
+const FILETIME_UNIX_EPOCH_INTERVALS: u64 = 11_644_473_600 * 10_000_000;
+
 fn get_realtime_time() -> Result<Duration> {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|_| Error::EFAULT)
+    let mut filetime: FILETIME = unsafe { std::mem::zeroed() };
+    unsafe {
+        match *GET_SYSTEM_TIME_PRECISE_AS_FILE_TIME {
+            Some(get_system_time_precise_as_file_time) => {
+                get_system_time_precise_as_file_time(&mut filetime)
+            }
+            None => GetSystemTimeAsFileTime(&mut filetime),
+        }
+    }
+
+    let intervals =
+        (u64::from(filetime.dwHighDateTime) << 32) | u64::from(filetime.dwLowDateTime);
+    let unix_intervals = intervals.saturating_sub(FILETIME_UNIX_EPOCH_INTERVALS);
+    Ok(Duration::from_nanos(unix_intervals * 100))
 }
 
 fn get_proc_cputime() -> Result<Duration> {